@@ -0,0 +1,163 @@
+//! User configuration, loaded from `~/.config/gnome-who/config.toml`.
+
+use crate::GREETER_HOST;
+use anyhow::{Context, Error, Result};
+use inotify::{Inotify, WatchDescriptor, WatchMask};
+use mio::unix::SourceFd;
+use mio::{Interest, Registry, Token};
+use serde::Deserialize;
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+
+const CONFIG_DIR_NAME: &str = "gnome-who";
+const CONFIG_FILE_NAME: &str = "config.toml";
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub filter: FilterConfig,
+    pub notification: NotificationConfig,
+    pub utmp: UtmpConfig,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FilterConfig {
+    /// Sessions from these hosts don't trigger the warning state.
+    pub ignored_hosts: Vec<String>,
+    /// Sessions of these users don't trigger the warning state.
+    pub ignored_users: Vec<String>,
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        FilterConfig {
+            ignored_hosts: vec![GREETER_HOST.to_string()],
+            ignored_users: Vec::new(),
+        }
+    }
+}
+
+impl FilterConfig {
+    /// Whether sessions of the given user from the given host are ignored.
+    pub fn should_ignore(&self, user: &str, host: &str) -> bool {
+        self.ignored_hosts.iter().any(|h| h == host) || self.ignored_users.iter().any(|u| u == user)
+    }
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotificationConfig {
+    /// Whether to notify when a session goes away.
+    pub on_logout: bool,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UtmpConfig {
+    /// Whether records which can't be listed trigger the warning state.
+    pub warn_on_unrecognized: bool,
+}
+
+fn config_dir() -> PathBuf {
+    glib::user_config_dir().join(CONFIG_DIR_NAME)
+}
+
+/// Load the config file, or the default config if it doesn't exist.
+pub fn load() -> Result<Config> {
+    let path = config_dir().join(CONFIG_FILE_NAME);
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Config::default()),
+        Err(e) => return Err(Error::new(e).context("failed to read config file")),
+    };
+    toml::from_str(&content).with_context(|| format!("invalid config file {}", path.display()))
+}
+
+/// Watcher for changes to the config file.
+pub struct ConfigWatcher {
+    inotify: Inotify,
+    /// Watch on the parent of the config directory, for noticing the config
+    /// directory being created.
+    parent_watch: Option<WatchDescriptor>,
+    inotify_buffer: [u8; 4096],
+}
+
+impl ConfigWatcher {
+    pub fn new() -> Result<Self> {
+        let inotify = Inotify::init().context("failed to init inotify")?;
+        // If even the parent doesn't exist, there is nothing to watch, and
+        // the default config stays in use.
+        let mask = WatchMask::CREATE | WatchMask::MOVED_TO | WatchMask::ONLYDIR;
+        let parent_watch = match inotify.watches().add(glib::user_config_dir(), mask) {
+            Ok(watch) => Some(watch),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(Error::new(e).context("failed to watch user config directory")),
+        };
+        let mut watcher = ConfigWatcher {
+            inotify,
+            parent_watch,
+            inotify_buffer: [0; 4096],
+        };
+        watcher.watch_config_dir()?;
+        Ok(watcher)
+    }
+
+    /// Watch the config directory if it exists.
+    fn watch_config_dir(&mut self) -> Result<()> {
+        // Watch the directory rather than the file, since editors commonly
+        // save by replacing the file.
+        let mask = WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::DELETE;
+        match self.inotify.watches().add(config_dir(), mask) {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(Error::new(e).context("failed to watch config directory")),
+        }
+    }
+
+    pub fn register(&mut self, registry: &Registry, token: Token) -> Result<()> {
+        registry
+            .register(
+                &mut SourceFd(&self.inotify.as_raw_fd()),
+                token,
+                Interest::READABLE,
+            )
+            .context("failed to register config watcher")
+    }
+
+    /// Drain pending events, and return whether the config file has changed.
+    pub fn handle_event(&mut self) -> Result<bool> {
+        let mut changed = false;
+        let mut dir_created = false;
+        loop {
+            let events = match self.inotify.read_events(&mut self.inotify_buffer) {
+                Ok(events) => events,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(Error::new(e).context("failed to read inotify events")),
+            };
+            let mut count = 0;
+            for event in events {
+                if Some(&event.wd) == self.parent_watch.as_ref() {
+                    if event.name.is_some_and(|name| name == CONFIG_DIR_NAME) {
+                        dir_created = true;
+                    }
+                } else if event.name.is_some_and(|name| name == CONFIG_FILE_NAME) {
+                    changed = true;
+                }
+                count += 1;
+            }
+            if count == 0 {
+                break;
+            }
+        }
+        // The config file may have been written before we start watching the
+        // new directory, so always reload in this case.
+        if dir_created {
+            self.watch_config_dir()?;
+            changed = true;
+        }
+        Ok(changed)
+    }
+}
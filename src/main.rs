@@ -1,64 +1,197 @@
 use anyhow::{Context, Error, Result};
+use config::{ConfigWatcher, FilterConfig};
 use futures_channel::mpsc::UnboundedReceiver;
 use futures_util::StreamExt;
 use glib::MainContext;
 use gtk::prelude::*;
 use gtk::{
     ButtonsType, CheckMenuItem, DialogFlags, Menu, MenuItem, MessageDialog, MessageType,
-    SeparatorMenuItem, Window,
+    ResponseType, SeparatorMenuItem, Window,
 };
-use inotify::{Inotify, WatchMask};
 use libappindicator::{AppIndicator, AppIndicatorStatus};
-use mio::unix::SourceFd;
 use mio::{Events, Interest, Poll, Token};
 use mio_pidfd::PidFd;
 use nix::errno::Errno;
 use nix::sys::signal::{self, Signal};
 use nix::unistd::Pid;
-use once_cell::sync::Lazy;
+use notify_rust::Notification;
+use source::{Record, Records};
+use std::cell::Cell;
 use std::collections::HashMap;
 use std::env;
 use std::fmt::Write as _;
 use std::fs;
 use std::io;
-use std::io::ErrorKind;
 use std::mem;
-use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use time::format_description::FormatItem;
 use time::macros::format_description;
-use time::UtcOffset;
-use utmp_rs::UtmpEntry;
+use time::{OffsetDateTime, UtcOffset};
+
+mod config;
+mod source;
 
-const UTMP_PATH: &str = "/var/run/utmp";
 const NORMAL_ICON: &[u8] = include_bytes!("../icons/normal.svg");
 const WARNING_ICON: &[u8] = include_bytes!("../icons/warning.svg");
 
-const IGNORED_HOSTS: &[&str] = &["login screen"];
+/// How often to check whether the session source has become available.
+const SOURCE_PROBE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Token for the session source. Pid fds use the pid as token, which is never 0.
+const SOURCE_TOKEN: Token = Token(0);
+/// Token for the config watcher, which is way beyond the range of pids.
+const CONFIG_TOKEN: Token = Token(usize::MAX);
+
+/// Seconds to wait after SIGTERM before offering to kill a session with SIGKILL.
+const TERMINATE_GRACE_PERIOD: u32 = 5;
+
+const TERMINATE_RESPONSE: ResponseType = ResponseType::Other(1);
+const KILL_RESPONSE: ResponseType = ResponseType::Other(2);
+
+/// Identifier of the notification action for terminating the session.
+const TERMINATE_ACTION: &str = "terminate";
+/// Maximum number of notifications offering the terminate action at a time.
+/// Each of them needs a thread waiting until it's closed, which can take hours,
+/// so further notifications are shown without the action.
+const MAX_PENDING_ACTIONS: usize = 4;
+
+/// Number of notifications currently offering the terminate action.
+static PENDING_ACTIONS: AtomicUsize = AtomicUsize::new(0);
 
-static DISPLAY: Lazy<String> = Lazy::new(|| env::var("DISPLAY").expect("no DISPLAY specified"));
+/// Host of the records display managers create for their greeters.
+const GREETER_HOST: &str = "login screen";
+
+/// Information for telling which session is the one we are running in.
+struct CurrentSession {
+    /// Our session id, from `XDG_SESSION_ID` or the audit session of ours.
+    id: Option<String>,
+    /// Our X display, for when session ids don't tell.
+    display: Option<String>,
+}
+
+impl CurrentSession {
+    fn detect() -> Self {
+        let id = env::var("XDG_SESSION_ID")
+            .ok()
+            .or_else(|| source::audit_session_id(None));
+        let display = env::var("DISPLAY").ok();
+        CurrentSession { id, display }
+    }
+
+    fn matches(&self, record: &Record) -> bool {
+        // Our id doesn't always belong to a login session, e.g. when we are
+        // started by the systemd user manager, the audit session is that of
+        // the manager, so still check the display when the id doesn't match.
+        let id_matches = matches!(
+            (&self.id, &record.session_id),
+            (Some(id), Some(session_id)) if id == session_id
+        );
+        id_matches || self.display.as_ref() == Some(&record.line)
+    }
+}
 
 enum Message {
-    Update(Vec<Entry>),
+    /// New snapshot of sessions, or `None` if the session source currently has no data.
+    Update(Option<Snapshot>),
+    /// Reloading the config failed, so the previous config stays in use.
+    ConfigError(Error),
     Error(Error),
 }
 
+#[derive(Default)]
+struct Snapshot {
+    entries: Vec<Entry>,
+    /// Records of types we don't know about, as pairs of record index and type.
+    unrecognized: Vec<(usize, i16)>,
+    /// Indices of records of known types which fail to parse.
+    unparseable: Vec<usize>,
+    /// Whether unrecognized and unparseable records trigger the warning state.
+    warn_on_unrecognized: bool,
+    /// Sessions which have appeared since the previous snapshot. This includes
+    /// sessions which have already gone, but not the current one nor ignored ones.
+    added: Vec<SessionChange>,
+    /// Labels of sessions which have gone since the previous snapshot, excluding
+    /// the current one and ignored ones. Only reported when notifying about
+    /// logouts is enabled.
+    removed: Vec<String>,
+}
+
+struct SessionChange {
+    pid: Pid,
+    label: String,
+    can_kill: bool,
+}
+
 struct Entry {
     pid: Pid,
     label: String,
     is_current: bool,
     should_ignore: bool,
-    can_kill: bool,
+    /// Whether this is an ignored display manager greeter session. Some display
+    /// managers leave many of them around, so they are not tracked individually.
+    is_greeter: bool,
+    kill_status: KillStatus,
+}
+
+/// Whether a session can be killed from the menu, and if not, why.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum KillStatus {
+    Allowed,
+    /// It is the session we are running in.
+    Current,
+    /// We are not permitted to signal the session leader.
+    NoPermission,
+    /// Checking the session leader failed for some other reason.
+    Unknown,
+}
+
+impl KillStatus {
+    /// Returns the kill status of the given session leader,
+    /// or `None` if the process no longer exists.
+    fn of(pid: Pid, is_current: bool) -> Option<Self> {
+        Self::from_probe(signal::kill(pid, None), is_current)
+    }
+
+    /// Derive the kill status from the result of probing the session leader
+    /// with signal 0.
+    fn from_probe(probe: nix::Result<()>, is_current: bool) -> Option<Self> {
+        let status = match probe {
+            Err(Errno::ESRCH) => return None,
+            _ if is_current => KillStatus::Current,
+            Ok(()) => KillStatus::Allowed,
+            Err(Errno::EPERM) => KillStatus::NoPermission,
+            Err(_) => KillStatus::Unknown,
+        };
+        Some(status)
+    }
+
+    /// Short reason for the session not being killable, to be shown along
+    /// with its label, since the panel doesn't show tooltips of menu items.
+    fn reason(self) -> Option<&'static str> {
+        match self {
+            KillStatus::Allowed => None,
+            KillStatus::Current => Some("current session"),
+            KillStatus::NoPermission => Some("no permission to kill"),
+            KillStatus::Unknown => Some("unable to check"),
+        }
+    }
 }
 
 fn main() -> Result<()> {
+    // The local offset can only be determined while the process is still
+    // single-threaded, so it has to be done before initializing GTK.
+    let offset = UtcOffset::current_local_offset().ok();
+    let notify = !env::args().skip(1).any(|arg| arg == "--no-notify");
+
     gtk::init().context("failed to init GTK")?;
 
     let (tx, rx) = futures_channel::mpsc::unbounded();
     thread::spawn(move || {
-        let result = watch_entries(|entries| {
-            let _ = tx.unbounded_send(Message::Update(entries));
+        let result = watch_entries(offset, |msg| {
+            let _ = tx.unbounded_send(msg);
         });
         match result {
             Ok(()) => unreachable!(),
@@ -78,7 +211,7 @@ fn main() -> Result<()> {
     indicator.set_icon_theme_path(temp_path.to_str().unwrap());
     indicator.set_status(AppIndicatorStatus::Active);
 
-    MainContext::default().spawn_local(handle_messages(indicator, rx));
+    MainContext::default().spawn_local(handle_messages(indicator, rx, notify));
 
     gtk::main();
     Ok(())
@@ -91,80 +224,96 @@ const GENERAL_TIME_FORMAT: &[FormatItem<'_>] = format_description!(
          sign:mandatory]:[offset_minute]:[offset_second]"
 );
 
-fn watch_entries(f: impl Fn(Vec<Entry>)) -> Result<()> {
-    let mut poll = Poll::new().context("failed to create poll")?;
+/// Format the time in the given local offset, or with an explicit offset
+/// when it is `None`.
+fn format_time(time: OffsetDateTime, offset: Option<UtcOffset>) -> String {
+    match offset {
+        Some(offset) => time.to_offset(offset).format(LOCAL_TIME_FORMAT).unwrap(),
+        None => time.format(GENERAL_TIME_FORMAT).unwrap(),
+    }
+}
 
-    let mut inotify = Inotify::init().context("failed to init inotify")?;
-    inotify
-        .watches()
-        .add(UTMP_PATH, WatchMask::CLOSE_WRITE)
-        .context("failed to watch utmp file")?;
-    poll.registry().register(
-        &mut SourceFd(&inotify.as_raw_fd()),
-        Token(0),
-        Interest::READABLE,
-    )?;
+/// Watch login sessions and report snapshots of them, as well as errors which
+/// don't stop the watching, via `f`.
+///
+/// Login times are shown in the given local offset, or with an explicit
+/// offset when it is `None`.
+fn watch_entries(offset: Option<UtcOffset>, f: impl Fn(Message)) -> Result<()> {
+    let current_session = CurrentSession::detect();
+    let mut config = config::load()?;
+    let mut config_watcher = ConfigWatcher::new()?;
+    let mut source = source::open()?;
+    let mut poll = Poll::new().context("failed to create poll")?;
+    source.register(poll.registry(), SOURCE_TOKEN)?;
+    config_watcher.register(poll.registry(), CONFIG_TOKEN)?;
 
     let mut events = Events::with_capacity(1024);
-    let mut inotify_buffer = [0u8; 4096];
     let mut pid_map = HashMap::new();
+    let mut session_tracker = SessionTracker::default();
+    let mut was_available = None;
     loop {
-        // Generate all valid entries from utmp.
-        let entries = utmp_rs::parse_from_path(UTMP_PATH)
-            .context("failed to read utmp")?
-            .into_iter()
-            .filter_map(|entry| {
-                if let UtmpEntry::UserProcess {
-                    pid,
-                    user,
-                    line,
-                    host,
-                    time,
-                    ..
-                } = entry
-                {
-                    let pid = Pid::from_raw(pid);
-                    let can_kill = match signal::kill(pid, None) {
-                        // Skip processes no longer exist.
-                        Err(Errno::ESRCH) => return None,
-                        Err(Errno::EPERM) => false,
-                        _ => true,
-                    };
-                    let offset = UtcOffset::local_offset_at(time).ok();
-                    let time = match offset {
-                        Some(offset) => time.to_offset(offset).format(LOCAL_TIME_FORMAT).unwrap(),
-                        None => time.format(GENERAL_TIME_FORMAT).unwrap(),
-                    };
-                    let mut label = format!("{} - {} / {}", time, user, line);
-                    if !host.is_empty() {
-                        write!(&mut label, " @ {}", host).unwrap();
-                    }
-                    let is_current = line == *DISPLAY;
-                    let should_ignore = IGNORED_HOSTS.iter().any(|s| host == *s);
-                    Some(Entry {
-                        pid,
-                        label,
-                        is_current,
-                        should_ignore,
-                        can_kill,
-                    })
-                } else {
-                    None
-                }
-            })
-            .collect::<Vec<_>>();
+        let records = source.read()?;
+        let is_available = records.is_some();
+        let timeout = if is_available {
+            None
+        } else {
+            Some(SOURCE_PROBE_INTERVAL)
+        };
+        let Records {
+            records,
+            unrecognized,
+            unparseable,
+        } = records.unwrap_or_default();
+
+        // Generate all valid entries from the records.
+        let mut entries = Vec::new();
+        let mut seen_sessions = Vec::new();
+        for record in records {
+            let is_current = current_session.matches(&record);
+            let Description {
+                label,
+                should_ignore,
+                is_greeter,
+            } = describe(&record, offset, &config.filter);
+            let Record { pid, line, .. } = record;
+            let kill_status = KillStatus::of(pid, is_current);
+            seen_sessions.push(SeenSession {
+                pid,
+                line,
+                label: label.clone(),
+                should_notify: !is_current && !should_ignore,
+                can_kill: kill_status == Some(KillStatus::Allowed),
+            });
+            // Skip processes no longer exist.
+            let kill_status = match kill_status {
+                Some(kill_status) => kill_status,
+                None => continue,
+            };
+            entries.push(Entry {
+                pid,
+                label,
+                is_current,
+                should_ignore,
+                is_greeter,
+                kill_status,
+            });
+        }
+        let (added, removed) = session_tracker.update(
+            is_available.then_some(seen_sessions.as_slice()),
+            config.notification.on_logout,
+        );
 
         let registry = poll.registry();
         let mut old_pid_map = mem::take(&mut pid_map);
-        for Entry { pid, .. } in entries.iter() {
-            if let Some((pid, fd)) = old_pid_map.remove_entry(pid) {
+        for pid in pids_to_watch(&entries) {
+            if let Some((pid, fd)) = old_pid_map.remove_entry(&pid) {
                 pid_map.insert(pid, fd);
             } else {
                 let mut fd = PidFd::open(pid.as_raw(), 0).context("failed to open pid fd")?;
                 registry
                     .register(&mut fd, Token(pid.as_raw() as usize), Interest::READABLE)
                     .context("failed to register pid fd")?;
-                pid_map.insert(*pid, fd);
+                pid_map.insert(pid, fd);
             }
         }
         for (_, mut fd) in old_pid_map.into_iter() {
@@ -173,39 +322,193 @@ fn watch_entries(f: impl Fn(Vec<Entry>)) -> Result<()> {
                 .context("failed to deregister")?;
         }
 
-        f(entries);
+        if is_available {
+            f(Message::Update(Some(Snapshot {
+                entries,
+                unrecognized,
+                unparseable,
+                warn_on_unrecognized: config.utmp.warn_on_unrecognized,
+                added,
+                removed,
+            })));
+        } else if was_available != Some(false) {
+            f(Message::Update(None));
+        }
+        was_available = Some(is_available);
+
         loop {
-            match poll.poll(&mut events, None) {
+            match poll.poll(&mut events, timeout) {
                 Ok(()) => break,
                 Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
                 Err(e) => return Err(Error::new(e).context("failed to poll")),
             }
         }
 
-        if events.iter().any(|e| e.token() == Token(0)) {
-            // Drain the inotify events if it's pending.
-            loop {
-                let events = inotify
-                    .read_events(&mut inotify_buffer)
-                    .map(|iter| iter.count())
-                    .or_else(|err| match err.kind() {
-                        ErrorKind::WouldBlock => Ok(0),
-                        _ => Err(err),
-                    })
-                    .context("failed to read inotify events")?;
-                if events == 0 {
-                    break;
-                }
+        if events.iter().any(|e| e.token() == SOURCE_TOKEN) {
+            source.handle_event()?;
+        }
+        if events.iter().any(|e| e.token() == CONFIG_TOKEN) && config_watcher.handle_event()? {
+            // Keep using the previous config if the new one is broken,
+            // so that a typo while editing doesn't bring us down.
+            match config::load() {
+                Ok(new_config) => config = new_config,
+                Err(e) => f(Message::ConfigError(e)),
+            }
+        }
+    }
+}
+
+/// How a record is presented.
+struct Description {
+    label: String,
+    should_ignore: bool,
+    /// Whether this is an ignored display manager greeter session.
+    is_greeter: bool,
+}
+
+fn describe(record: &Record, offset: Option<UtcOffset>, filter: &FilterConfig) -> Description {
+    let Record {
+        user,
+        line,
+        host,
+        time,
+        ..
+    } = record;
+    let time = format_time(*time, offset);
+    // Some broken sshd builds write user process records
+    // without a user, which should stand out rather than
+    // blending in with the other entries.
+    let has_user = !user.trim().is_empty();
+    let user: &str = if has_user { user } else { "(no user)" };
+    let mut label = format!("{} - {} / {}", time, user, line);
+    if !host.is_empty() {
+        write!(&mut label, " @ {}", host).unwrap();
+    }
+    let should_ignore = has_user && filter.should_ignore(user, host);
+    // Only collapse greeters which are ignored anyway, so that
+    // folding them never hides a warning.
+    let is_greeter = should_ignore && host == GREETER_HOST;
+    Description {
+        label,
+        should_ignore,
+        is_greeter,
+    }
+}
+
+/// Pids of session leaders to watch via pid fd.
+fn pids_to_watch(entries: &[Entry]) -> impl Iterator<Item = Pid> + '_ {
+    // Greeters are not watched via pid fd, as there can be lots of them.
+    // Changes to them would be picked up by changes from the source.
+    entries.iter().filter(|e| !e.is_greeter).map(|e| e.pid)
+}
+
+/// Take greeters out of the entries to show them as a single item, if there
+/// are more than one of them. Returns the entries to show individually, and
+/// the number of greeters taken out.
+fn collapse_greeters(entries: Vec<Entry>) -> (Vec<Entry>, usize) {
+    let (greeters, mut entries): (Vec<_>, Vec<_>) =
+        entries.into_iter().partition(|entry| entry.is_greeter);
+    if greeters.len() > 1 {
+        return (entries, greeters.len());
+    }
+    entries.extend(greeters);
+    (entries, 0)
+}
+
+/// A session found in a scan, for tracking sessions across scans.
+struct SeenSession {
+    pid: Pid,
+    line: String,
+    label: String,
+    /// Whether the session is worth notifying about, i.e. it's neither the
+    /// current one nor an ignored one.
+    should_notify: bool,
+    can_kill: bool,
+}
+
+/// Tracker of sessions appearing and going between scans.
+#[derive(Default)]
+struct SessionTracker {
+    /// Sessions seen in the last scan keyed by pid and line, with their labels
+    /// if they are worth notifying about. It's `None` before the first scan,
+    /// since sessions existing at startup are not new.
+    known: Option<HashMap<(Pid, String), Option<String>>>,
+}
+
+impl SessionTracker {
+    /// Update with the sessions of a new scan, or `None` if the source has no
+    /// data. Returns sessions worth notifying about which have appeared since
+    /// the last scan, and labels of those which have gone if `on_logout`.
+    fn update(
+        &mut self,
+        sessions: Option<&[SeenSession]>,
+        on_logout: bool,
+    ) -> (Vec<SessionChange>, Vec<String>) {
+        // Don't forget sessions we know while the source is unavailable,
+        // otherwise they would all be reported as new once it's back.
+        let sessions = match sessions {
+            Some(sessions) => sessions,
+            None => return (Vec::new(), Vec::new()),
+        };
+        let known = self.known.take();
+        let mut added = Vec::new();
+        let mut seen = HashMap::new();
+        for session in sessions {
+            let key = (session.pid, session.line.clone());
+            let is_new = known
+                .as_ref()
+                .is_some_and(|known| !known.contains_key(&key));
+            if is_new && session.should_notify {
+                added.push(SessionChange {
+                    pid: session.pid,
+                    label: session.label.clone(),
+                    can_kill: session.can_kill,
+                });
             }
+            seen.insert(key, session.should_notify.then(|| session.label.clone()));
         }
+        let removed = match &known {
+            Some(known) if on_logout => known
+                .iter()
+                .filter(|(key, _)| !seen.contains_key(*key))
+                .filter_map(|(_, label)| label.clone())
+                .collect(),
+            _ => Vec::new(),
+        };
+        self.known = Some(seen);
+        (added, removed)
     }
 }
 
-async fn handle_messages(mut indicator: AppIndicator, mut rx: UnboundedReceiver<Message>) {
+async fn handle_messages(
+    mut indicator: AppIndicator,
+    mut rx: UnboundedReceiver<Message>,
+    notify: bool,
+) {
     while let Some(msg) = rx.next().await {
         match msg {
-            Message::Update(entries) => {
-                update_indicator(&mut indicator, entries);
+            Message::Update(snapshot) => {
+                if let Some(snapshot) = snapshot.as_ref().filter(|_| notify) {
+                    for session in snapshot.added.iter() {
+                        notify_session_added(session);
+                    }
+                    for label in snapshot.removed.iter() {
+                        notify_session_removed(label);
+                    }
+                }
+                update_indicator(&mut indicator, snapshot);
+            }
+            Message::ConfigError(e) => {
+                let message = format!("Keeping the previous config.\n\n{:?}", e);
+                let dialog = MessageDialog::new::<Window>(
+                    None,
+                    DialogFlags::MODAL,
+                    MessageType::Warning,
+                    ButtonsType::Ok,
+                    &message,
+                );
+                dialog.connect_response(|dialog, _| dialog.close());
+                dialog.show_all();
             }
             Message::Error(e) => {
                 let message = format!("{:?}", e);
@@ -224,28 +527,124 @@ async fn handle_messages(mut indicator: AppIndicator, mut rx: UnboundedReceiver<
     }
 }
 
-fn update_indicator(indicator: &mut AppIndicator, entries: Vec<Entry>) {
+fn notify_session_added(session: &SessionChange) {
+    // Resolve the pid now while the session is known to be alive, as the
+    // notification can stay around for hours, by when the pid may have been
+    // reused by another process.
+    let action = if session.can_kill {
+        PendingAction::reserve()
+            .and_then(|slot| Some((slot, PidFd::open(session.pid.as_raw(), 0).ok()?)))
+    } else {
+        None
+    };
+    let mut notification = Notification::new();
+    notification
+        .appname("gnome-who")
+        .summary("New login session")
+        .body(&session.label)
+        .icon("dialog-warning");
+    if action.is_some() {
+        notification.action(TERMINATE_ACTION, "Terminate…");
+    }
+    // Failing to notify is not fatal, as the indicator would still change.
+    let handle = match notification.show() {
+        Ok(handle) => handle,
+        Err(_) => return,
+    };
+    let (slot, fd) = match action {
+        Some(action) => action,
+        None => return,
+    };
+    let label = session.label.clone();
+    // Waiting for the action blocks until the notification is closed,
+    // so do that on another thread, and come back for the confirmation.
+    thread::spawn(move || {
+        let _slot = slot;
+        handle.wait_for_action(|action| {
+            if action == TERMINATE_ACTION {
+                MainContext::default().invoke(move || confirm_terminate(fd, &label));
+            }
+        });
+    });
+}
+
+/// A slot for a notification offering the terminate action, which is
+/// released when dropped.
+struct PendingAction;
+
+impl PendingAction {
+    /// Reserve a slot, or return `None` if there are too many already.
+    fn reserve() -> Option<Self> {
+        PENDING_ACTIONS
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| {
+                (count < MAX_PENDING_ACTIONS).then_some(count + 1)
+            })
+            .ok()?;
+        Some(PendingAction)
+    }
+}
+
+impl Drop for PendingAction {
+    fn drop(&mut self) {
+        PENDING_ACTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn notify_session_removed(label: &str) {
+    let _ = Notification::new()
+        .appname("gnome-who")
+        .summary("Login session ended")
+        .body(label)
+        .icon("dialog-information")
+        .show();
+}
+
+fn update_indicator(indicator: &mut AppIndicator, snapshot: Option<Snapshot>) {
     let mut menu = Menu::new();
     let mut has_non_current = false;
+    let Snapshot {
+        entries,
+        unrecognized,
+        unparseable,
+        warn_on_unrecognized,
+        ..
+    } = snapshot.unwrap_or_else(|| {
+        let item = MenuItem::with_label("No session data available");
+        item.set_sensitive(false);
+        menu.append(&item);
+        Snapshot::default()
+    });
+    let (entries, greeter_count) = collapse_greeters(entries);
     for Entry {
         pid,
         label,
         is_current,
         should_ignore,
-        can_kill,
+        kill_status,
+        ..
     } in entries.into_iter()
     {
+        let item_label = match kill_status.reason() {
+            Some(reason) => format!("{} ({})", label, reason),
+            None => label.clone(),
+        };
         if is_current {
-            let item = CheckMenuItem::with_label(&label);
+            let item = CheckMenuItem::with_label(&item_label);
             item.set_active(true);
             item.set_sensitive(false);
             item.set_draw_as_radio(true);
             menu.append(&item);
         } else {
-            let item = MenuItem::with_label(&label);
-            item.set_sensitive(can_kill);
+            let item = MenuItem::with_label(&item_label);
+            item.set_sensitive(kill_status == KillStatus::Allowed);
             item.connect_activate(move |_| {
-                let _ = signal::kill(pid, Signal::SIGKILL);
+                // Resolve the pid right away, so that the dialog would never
+                // lead to signaling another process reusing the pid.
+                // This fails if the process has exited already, in which case
+                // the next update would drop the item.
+                if let Ok(fd) = PidFd::open(pid.as_raw(), 0) {
+                    confirm_terminate(fd, &label);
+                }
             });
             menu.append(&item);
             if !should_ignore {
@@ -253,6 +652,24 @@ fn update_indicator(indicator: &mut AppIndicator, entries: Vec<Entry>) {
             }
         }
     }
+    if greeter_count > 0 {
+        let item = MenuItem::with_label(&format!("Login screen ×{}", greeter_count));
+        item.set_sensitive(false);
+        menu.append(&item);
+    }
+    if !unrecognized.is_empty() {
+        let details = unrecognized
+            .iter()
+            .map(|(index, ty)| format!("Record {}: type {}", index, ty));
+        menu.append(&record_details_item("unrecognized", details));
+    }
+    if !unparseable.is_empty() {
+        let details = unparseable.iter().map(|index| format!("Record {}", index));
+        menu.append(&record_details_item("unparseable", details));
+    }
+    if warn_on_unrecognized && !(unrecognized.is_empty() && unparseable.is_empty()) {
+        has_non_current = true;
+    }
     menu.append(&SeparatorMenuItem::new());
     let quit_item = MenuItem::with_label("Quit");
     quit_item.connect_activate(|_| gtk::main_quit());
@@ -264,3 +681,339 @@ fn update_indicator(indicator: &mut AppIndicator, entries: Vec<Entry>) {
     let icon = if has_non_current { "warning" } else { "normal" };
     indicator.set_icon_full(icon, icon);
 }
+
+/// Create a menu item counting records of the given kind, with a line for
+/// each of them in its submenu.
+fn record_details_item(kind: &str, details: impl ExactSizeIterator<Item = String>) -> MenuItem {
+    let count = details.len();
+    let plural = if count == 1 { "" } else { "s" };
+    let item = MenuItem::with_label(&format!("{} {} record{}", count, kind, plural));
+    // Details go to a submenu, as the panel doesn't show tooltips.
+    let submenu = Menu::new();
+    for detail in details {
+        let detail = MenuItem::with_label(&detail);
+        detail.set_sensitive(false);
+        submenu.append(&detail);
+    }
+    submenu.show_all();
+    item.set_submenu(Some(&submenu));
+    item
+}
+
+fn confirm_terminate(fd: PidFd, label: &str) {
+    let message = format!("Terminate this session?\n\n{}", label);
+    let dialog = MessageDialog::new::<Window>(
+        None,
+        DialogFlags::MODAL,
+        MessageType::Question,
+        ButtonsType::None,
+        &message,
+    );
+    dialog.add_buttons(&[
+        ("Cancel", ResponseType::Cancel),
+        ("Terminate (SIGTERM)", TERMINATE_RESPONSE),
+        ("Kill (SIGKILL)", KILL_RESPONSE),
+    ]);
+    dialog.set_default_response(ResponseType::Cancel);
+    let label = label.to_string();
+    // The handler is called again when closing the dialog, so the pid fd
+    // needs to be taken out of it.
+    let fd = Cell::new(Some(fd));
+    dialog.connect_response(move |dialog, response| {
+        if let Some(fd) = fd.take() {
+            if response == TERMINATE_RESPONSE {
+                terminate_session(fd, &label);
+            } else if response == KILL_RESPONSE {
+                // This fails if the process has exited meanwhile, which is fine.
+                let _ = fd.kill(Signal::SIGKILL as i32);
+            }
+        }
+        dialog.close();
+    });
+    dialog.show_all();
+}
+
+/// Send SIGTERM to the session leader, and offer to kill it if it's still
+/// alive after the grace period.
+fn terminate_session(fd: PidFd, label: &str) {
+    // This fails if the process has exited on its own already.
+    if fd.kill(Signal::SIGTERM as i32).is_err() {
+        return;
+    }
+    let label = label.to_string();
+    glib::timeout_add_seconds_local_once(TERMINATE_GRACE_PERIOD, move || {
+        // Signal 0 only checks whether the process is still there.
+        if fd.kill(0).is_ok() {
+            confirm_kill(fd, &label);
+        }
+    });
+}
+
+fn confirm_kill(fd: PidFd, label: &str) {
+    let message = format!(
+        "This session is still running after SIGTERM. Kill it?\n\n{}",
+        label
+    );
+    let dialog = MessageDialog::new::<Window>(
+        None,
+        DialogFlags::MODAL,
+        MessageType::Warning,
+        ButtonsType::YesNo,
+        &message,
+    );
+    dialog.connect_response(move |dialog, response| {
+        if response == ResponseType::Yes {
+            // This fails if the process has exited meanwhile, which is fine.
+            let _ = fd.kill(Signal::SIGKILL as i32);
+        }
+        dialog.close();
+    });
+    dialog.show_all();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::{datetime, offset};
+
+    #[test]
+    fn kill_status_from_probe() {
+        assert!(KillStatus::from_probe(Err(Errno::ESRCH), false).is_none());
+        assert!(KillStatus::from_probe(Err(Errno::ESRCH), true).is_none());
+        let cases = [
+            (Ok(()), false, KillStatus::Allowed),
+            (Ok(()), true, KillStatus::Current),
+            (Err(Errno::EPERM), false, KillStatus::NoPermission),
+            (Err(Errno::EPERM), true, KillStatus::Current),
+            (Err(Errno::EINVAL), false, KillStatus::Unknown),
+        ];
+        for (probe, is_current, expected) in cases {
+            assert!(KillStatus::from_probe(probe, is_current) == Some(expected));
+        }
+    }
+
+    #[test]
+    fn format_time_in_local_offset() {
+        let time = datetime!(2021-03-04 23:30:00 UTC);
+        let formatted = format_time(time, Some(offset!(+8)));
+        assert_eq!(formatted, "2021-03-05 07:30:00");
+    }
+
+    #[test]
+    fn format_time_without_local_offset() {
+        let time = datetime!(2021-03-04 23:30:00 UTC);
+        let formatted = format_time(time, None);
+        assert_eq!(formatted, "2021-03-04 23:30:00 +00:00:00");
+    }
+
+    fn record(session_id: Option<&str>, line: &str) -> Record {
+        Record {
+            pid: Pid::from_raw(100),
+            user: "user".to_string(),
+            line: line.to_string(),
+            host: String::new(),
+            time: OffsetDateTime::UNIX_EPOCH,
+            session_id: session_id.map(str::to_string),
+        }
+    }
+
+    fn current_session(id: Option<&str>, display: Option<&str>) -> CurrentSession {
+        CurrentSession {
+            id: id.map(str::to_string),
+            display: display.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn current_session_matches_id() {
+        let current = current_session(Some("3"), None);
+        assert!(current.matches(&record(Some("3"), "tty2")));
+        assert!(!current.matches(&record(Some("4"), "tty3")));
+        assert!(!current.matches(&record(None, "tty3")));
+    }
+
+    #[test]
+    fn current_session_falls_back_to_display() {
+        // E.g. the audit session of the systemd user manager.
+        let current = current_session(Some("12"), Some(":0"));
+        assert!(current.matches(&record(Some("3"), ":0")));
+        assert!(current.matches(&record(None, ":0")));
+        assert!(!current.matches(&record(Some("3"), ":1")));
+        let current = current_session(None, Some(":0"));
+        assert!(current.matches(&record(Some("3"), ":0")));
+    }
+
+    #[test]
+    fn current_session_among_graphical_logins_of_same_user() {
+        // Running in a Wayland session, with Xwayland on a display other than
+        // the one of the X11 session.
+        let current = current_session(Some("5"), Some(":1"));
+        let x11 = record(Some("3"), ":0");
+        let wayland = record(Some("5"), "tty3");
+        assert!(!current.matches(&x11));
+        assert!(current.matches(&wayland));
+    }
+
+    fn user_record(pid: i32, user: &str, host: &str) -> Record {
+        Record {
+            pid: Pid::from_raw(pid),
+            user: user.to_string(),
+            line: "pts/0".to_string(),
+            host: host.to_string(),
+            time: OffsetDateTime::UNIX_EPOCH,
+            session_id: None,
+        }
+    }
+
+    fn entry(record: &Record, filter: &FilterConfig) -> Entry {
+        let Description {
+            label,
+            should_ignore,
+            is_greeter,
+        } = describe(record, None, filter);
+        Entry {
+            pid: record.pid,
+            label,
+            is_current: false,
+            should_ignore,
+            is_greeter,
+            kill_status: KillStatus::Allowed,
+        }
+    }
+
+    fn greeter_entries(count: i32, filter: &FilterConfig) -> Vec<Entry> {
+        (0..count)
+            .map(|i| entry(&user_record(1000 + i, "gdm", GREETER_HOST), filter))
+            .collect()
+    }
+
+    #[test]
+    fn many_greeters_are_collapsed() {
+        let filter = FilterConfig::default();
+        let mut entries = greeter_entries(14, &filter);
+        entries.push(entry(&user_record(100, "alice", "example.com"), &filter));
+        assert_eq!(
+            pids_to_watch(&entries).collect::<Vec<_>>(),
+            [Pid::from_raw(100)]
+        );
+        let (entries, greeter_count) = collapse_greeters(entries);
+        assert_eq!(greeter_count, 14);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].pid, Pid::from_raw(100));
+    }
+
+    #[test]
+    fn single_greeter_is_not_collapsed() {
+        let entries = greeter_entries(1, &FilterConfig::default());
+        assert_eq!(pids_to_watch(&entries).count(), 0);
+        let (entries, greeter_count) = collapse_greeters(entries);
+        assert_eq!(greeter_count, 0);
+        assert_eq!(entries.len(), 1);
+    }
+
+    #[test]
+    fn greeters_not_ignored_are_not_collapsed() {
+        let filter = FilterConfig {
+            ignored_hosts: Vec::new(),
+            ignored_users: Vec::new(),
+        };
+        let entries = greeter_entries(3, &filter);
+        assert!(entries.iter().all(|e| !e.should_ignore && !e.is_greeter));
+        assert_eq!(pids_to_watch(&entries).count(), 3);
+        let (entries, greeter_count) = collapse_greeters(entries);
+        assert_eq!(greeter_count, 0);
+        assert_eq!(entries.len(), 3);
+    }
+
+    fn seen_session(pid: i32, line: &str, should_notify: bool) -> SeenSession {
+        SeenSession {
+            pid: Pid::from_raw(pid),
+            line: line.to_string(),
+            label: format!("{} {}", pid, line),
+            should_notify,
+            can_kill: true,
+        }
+    }
+
+    fn labels(added: &[SessionChange]) -> Vec<&str> {
+        added.iter().map(|session| session.label.as_str()).collect()
+    }
+
+    #[test]
+    fn first_scan_does_not_notify() {
+        let mut tracker = SessionTracker::default();
+        let (added, removed) = tracker.update(Some(&[seen_session(100, "pts/0", true)]), true);
+        assert!(added.is_empty());
+        assert!(removed.is_empty());
+    }
+
+    #[test]
+    fn sessions_are_notified_once() {
+        let mut tracker = SessionTracker::default();
+        tracker.update(Some(&[seen_session(100, "pts/0", true)]), true);
+        let sessions = [
+            seen_session(100, "pts/0", true),
+            seen_session(101, "pts/1", true),
+        ];
+        let (added, removed) = tracker.update(Some(&sessions), true);
+        assert_eq!(labels(&added), ["101 pts/1"]);
+        assert!(removed.is_empty());
+        let (added, removed) = tracker.update(Some(&sessions), true);
+        assert!(added.is_empty());
+        assert!(removed.is_empty());
+        let (added, removed) = tracker.update(Some(&sessions[1..]), true);
+        assert!(added.is_empty());
+        assert_eq!(removed, ["100 pts/0"]);
+    }
+
+    #[test]
+    fn sessions_are_keyed_by_pid_and_line() {
+        let mut tracker = SessionTracker::default();
+        tracker.update(Some(&[seen_session(100, "pts/0", true)]), true);
+        // The pid got reused by another session.
+        let (added, removed) = tracker.update(Some(&[seen_session(100, "pts/1", true)]), true);
+        assert_eq!(labels(&added), ["100 pts/1"]);
+        assert_eq!(removed, ["100 pts/0"]);
+        // The line got reused by another session.
+        let (added, removed) = tracker.update(Some(&[seen_session(101, "pts/1", true)]), true);
+        assert_eq!(labels(&added), ["101 pts/1"]);
+        assert_eq!(removed, ["100 pts/1"]);
+    }
+
+    #[test]
+    fn ignored_sessions_are_never_notified() {
+        let mut tracker = SessionTracker::default();
+        tracker.update(Some(&[]), true);
+        let (added, _) = tracker.update(Some(&[seen_session(100, "pts/0", false)]), true);
+        assert!(added.is_empty());
+        let (_, removed) = tracker.update(Some(&[]), true);
+        assert!(removed.is_empty());
+    }
+
+    #[test]
+    fn known_sessions_are_kept_while_unavailable() {
+        let mut tracker = SessionTracker::default();
+        tracker.update(Some(&[seen_session(100, "pts/0", true)]), true);
+        let (added, removed) = tracker.update(None, true);
+        assert!(added.is_empty());
+        assert!(removed.is_empty());
+        let sessions = [
+            seen_session(100, "pts/0", true),
+            seen_session(101, "pts/1", true),
+        ];
+        let (added, removed) = tracker.update(Some(&sessions), true);
+        assert_eq!(labels(&added), ["101 pts/1"]);
+        assert!(removed.is_empty());
+    }
+
+    #[test]
+    fn logouts_are_reported_only_when_enabled() {
+        let mut tracker = SessionTracker::default();
+        tracker.update(Some(&[seen_session(100, "pts/0", true)]), false);
+        let (_, removed) = tracker.update(Some(&[]), false);
+        assert!(removed.is_empty());
+        // Sessions gone while disabled are not reported later either.
+        let (_, removed) = tracker.update(Some(&[]), true);
+        assert!(removed.is_empty());
+    }
+}
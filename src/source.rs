@@ -0,0 +1,81 @@
+//! Sources of login sessions.
+
+use anyhow::{bail, Result};
+use mio::{Registry, Token};
+use nix::unistd::Pid;
+use std::env;
+use std::fs;
+use time::OffsetDateTime;
+
+mod logind;
+mod utmp;
+
+pub use self::logind::LogindSource;
+pub use self::utmp::UtmpSource;
+
+/// A login session reported by a source.
+pub struct Record {
+    /// Pid of the session leader.
+    pub pid: Pid,
+    pub user: String,
+    /// Terminal or display the session is on.
+    pub line: String,
+    /// Remote host the session comes from, empty for local sessions.
+    pub host: String,
+    /// Time the session started.
+    pub time: OffsetDateTime,
+    /// Id of the logind session, if known.
+    pub session_id: Option<String>,
+}
+
+#[derive(Default)]
+pub struct Records {
+    pub records: Vec<Record>,
+    /// Records of types we don't know about, as pairs of record index and type.
+    pub unrecognized: Vec<(usize, i16)>,
+    /// Indices of records of known types which fail to parse.
+    pub unparseable: Vec<usize>,
+}
+
+pub trait SessionSource {
+    /// Register the source to `registry`, so that `token` becomes ready when
+    /// sessions may have changed.
+    fn register(&mut self, registry: &Registry, token: Token) -> Result<()>;
+
+    /// Consume pending change notifications after `token` becomes ready.
+    fn handle_event(&mut self) -> Result<()>;
+
+    /// Read all current sessions, or `None` if the source has no data for now.
+    fn read(&mut self) -> Result<Option<Records>>;
+}
+
+/// Open the session source to use.
+///
+/// The source can be forced via `GNOME_WHO_SOURCE` environment variable with
+/// either `logind` or `utmp`. Otherwise logind is preferred when it's
+/// reachable, with utmp as the fallback.
+pub fn open() -> Result<Box<dyn SessionSource>> {
+    Ok(match env::var("GNOME_WHO_SOURCE").as_deref() {
+        Ok("logind") => Box::new(LogindSource::new()?),
+        Ok("utmp") => Box::new(UtmpSource::new()?),
+        Ok(source) => bail!("unknown session source `{}`", source),
+        Err(_) => match LogindSource::new() {
+            Ok(source) => Box::new(source),
+            Err(_) => Box::new(UtmpSource::new()?),
+        },
+    })
+}
+
+/// Returns the audit session id of the given process, or of the current
+/// process if `pid` is `None`. On systems with audit support, this is the
+/// same as the logind session id.
+pub fn audit_session_id(pid: Option<Pid>) -> Option<String> {
+    let path = match pid {
+        Some(pid) => format!("/proc/{}/sessionid", pid),
+        None => "/proc/self/sessionid".to_string(),
+    };
+    let id = fs::read_to_string(path).ok()?;
+    let id = id.trim();
+    // This is what the kernel reports for processes not in any session.
+    (id != "4294967295").then(|| id.to_string())
+}
@@ -0,0 +1,284 @@
+use super::{Record, Records, SessionSource};
+use anyhow::{anyhow, Context, Result};
+use gio::{BusType, Cancellable, DBusCallFlags, DBusConnection, DBusSignalFlags};
+use glib::variant::{FromVariant, ObjectPath};
+use glib::{MainContext, MainLoop, ToVariant, Variant, VariantDict, VariantTy};
+use mio::{Registry, Token, Waker};
+use nix::unistd::Pid;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use time::OffsetDateTime;
+
+const LOGIND_NAME: &str = "org.freedesktop.login1";
+const LOGIND_PATH: &str = "/org/freedesktop/login1";
+const MANAGER_INTERFACE: &str = "org.freedesktop.login1.Manager";
+const SESSION_INTERFACE: &str = "org.freedesktop.login1.Session";
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+
+/// Session source backed by systemd-logind over D-Bus.
+pub struct LogindSource {
+    connection: DBusConnection,
+}
+
+impl LogindSource {
+    pub fn new() -> Result<Self> {
+        let connection = gio::bus_get_sync(BusType::System, None::<&Cancellable>)
+            .context("failed to connect to system bus")?;
+        let source = LogindSource { connection };
+        // Make sure logind is actually there.
+        source.list_sessions()?;
+        Ok(source)
+    }
+
+    fn call(
+        &self,
+        path: &str,
+        interface: &str,
+        method: &str,
+        parameters: Option<&Variant>,
+        reply_type: &str,
+    ) -> Result<Variant, glib::Error> {
+        self.connection.call_sync(
+            Some(LOGIND_NAME),
+            path,
+            interface,
+            method,
+            parameters,
+            Some(VariantTy::new(reply_type).unwrap()),
+            DBusCallFlags::NONE,
+            -1,
+            None::<&Cancellable>,
+        )
+    }
+
+    /// Returns object paths of all sessions.
+    fn list_sessions(&self) -> Result<Vec<ObjectPath>> {
+        let reply = self
+            .call(
+                LOGIND_PATH,
+                MANAGER_INTERFACE,
+                "ListSessions",
+                None,
+                "(a(susso))",
+            )
+            .context("failed to list logind sessions")?;
+        let (sessions,) = reply
+            .get::<(Vec<(String, u32, String, String, ObjectPath)>,)>()
+            .unwrap();
+        Ok(sessions.into_iter().map(|(.., path)| path).collect())
+    }
+
+    /// Get the properties of the session at the given path, or `None` if it
+    /// no longer exists.
+    fn session_properties(&self, path: &str) -> Option<VariantDict> {
+        let parameters = (SESSION_INTERFACE,).to_variant();
+        // The session may have gone since it was listed.
+        let reply = self
+            .call(
+                path,
+                PROPERTIES_INTERFACE,
+                "GetAll",
+                Some(&parameters),
+                "(a{sv})",
+            )
+            .ok()?;
+        Some(VariantDict::new(Some(&reply.child_value(0))))
+    }
+}
+
+/// Build a record from the properties of a session, or `None` if it should
+/// not be listed.
+fn session_record(properties: &VariantDict) -> Result<Option<Record>> {
+    // Only list sessions which utmp would have, i.e. skip greeters,
+    // lock screens, and background sessions like cron jobs.
+    let class = property::<String>(properties, "Class")?;
+    let state = property::<String>(properties, "State")?;
+    let leader = property::<u32>(properties, "Leader")?;
+    if class != "user" || state == "closing" || leader == 0 {
+        return Ok(None);
+    }
+
+    let display = property::<String>(properties, "Display")?;
+    let tty = property::<String>(properties, "TTY")?;
+    let (seat, _) = property::<(String, ObjectPath)>(properties, "Seat")?;
+    let line = [display, tty, seat]
+        .into_iter()
+        .find(|s| !s.is_empty())
+        .unwrap_or_default();
+    let timestamp = property::<u64>(properties, "Timestamp")?;
+    let time = OffsetDateTime::from_unix_timestamp_nanos(i128::from(timestamp) * 1000)
+        .context("invalid session timestamp")?;
+    Ok(Some(Record {
+        pid: Pid::from_raw(leader as i32),
+        user: property(properties, "Name")?,
+        line,
+        host: property(properties, "RemoteHost")?,
+        time,
+        session_id: Some(property(properties, "Id")?),
+    }))
+}
+
+fn property<T: FromVariant>(properties: &VariantDict, name: &str) -> Result<T> {
+    properties
+        .lookup(name)
+        .ok()
+        .flatten()
+        .ok_or_else(|| anyhow!("missing or invalid session property {}", name))
+}
+
+impl SessionSource for LogindSource {
+    fn register(&mut self, registry: &Registry, token: Token) -> Result<()> {
+        let waker = Arc::new(Waker::new(registry, token).context("failed to create waker")?);
+        let connection = self.connection.clone();
+        let (tx, rx) = mpsc::channel();
+        // Signals are dispatched via the thread-default main context of the
+        // thread subscribing them, so run a dedicated one for that.
+        thread::spawn(move || {
+            let context = MainContext::new();
+            context
+                .with_thread_default(|| {
+                    for member in ["SessionNew", "SessionRemoved"] {
+                        let waker = waker.clone();
+                        connection.signal_subscribe(
+                            Some(LOGIND_NAME),
+                            Some(MANAGER_INTERFACE),
+                            Some(member),
+                            Some(LOGIND_PATH),
+                            None,
+                            DBusSignalFlags::NONE,
+                            move |_, _, _, _, _, _| {
+                                let _ = waker.wake();
+                            },
+                        );
+                    }
+                    let _ = tx.send(());
+                    MainLoop::new(Some(&context), false).run();
+                })
+                .expect("failed to acquire a new main context");
+        });
+        // Wait for the subscriptions, so that no change is missed after this.
+        rx.recv().context("failed to subscribe logind signals")
+    }
+
+    fn handle_event(&mut self) -> Result<()> {
+        // Nothing to drain for waker.
+        Ok(())
+    }
+
+    fn read(&mut self) -> Result<Option<Records>> {
+        // Failing to talk to logind is likely transient, e.g. while it's being
+        // restarted, so report that there is no data rather than bailing out,
+        // and it will be probed again later.
+        let paths = match self.list_sessions() {
+            Ok(paths) => paths,
+            Err(_) => return Ok(None),
+        };
+        let mut records = Vec::new();
+        let mut unparseable = Vec::new();
+        for (index, path) in paths.iter().enumerate() {
+            let properties = match self.session_properties(path) {
+                Some(properties) => properties,
+                None => continue,
+            };
+            match session_record(&properties) {
+                Ok(Some(record)) => records.push(record),
+                Ok(None) => {}
+                // Don't let a single odd session hide all the others.
+                Err(_) => unparseable.push(index),
+            }
+        }
+        Ok(Some(Records {
+            records,
+            unparseable,
+            ..Records::default()
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_session() -> VariantDict {
+        let properties = VariantDict::new(None);
+        properties.insert("Id", "3");
+        properties.insert("Name", "alice");
+        properties.insert("Class", "user");
+        properties.insert("State", "active");
+        properties.insert("Leader", 1234u32);
+        properties.insert("Display", "");
+        properties.insert("TTY", "pts/0");
+        properties.insert("Seat", seat(""));
+        properties.insert("RemoteHost", "example.com");
+        properties.insert("Timestamp", 1_700_000_000_123_456u64);
+        properties
+    }
+
+    fn seat(id: &str) -> (String, ObjectPath) {
+        let path = match id {
+            "" => "/".to_string(),
+            id => format!("{}/seat/{}", LOGIND_PATH, id),
+        };
+        (id.to_string(), ObjectPath::try_from(path).unwrap())
+    }
+
+    #[test]
+    fn sessions_are_mapped_to_records() {
+        let record = session_record(&user_session()).unwrap().unwrap();
+        assert_eq!(record.pid, Pid::from_raw(1234));
+        assert_eq!(record.user, "alice");
+        assert_eq!(record.line, "pts/0");
+        assert_eq!(record.host, "example.com");
+        assert_eq!(record.session_id.as_deref(), Some("3"));
+        // Timestamp is in microseconds.
+        assert_eq!(record.time.unix_timestamp(), 1_700_000_000);
+        assert_eq!(record.time.microsecond(), 123_456);
+    }
+
+    #[test]
+    fn non_user_sessions_are_skipped() {
+        let cases: [(&str, Variant); 4] = [
+            ("Class", "greeter".into()),
+            ("Class", "background".into()),
+            ("State", "closing".into()),
+            ("Leader", 0u32.into()),
+        ];
+        for (name, value) in cases {
+            let properties = user_session();
+            properties.insert_value(name, &value);
+            assert!(session_record(&properties).unwrap().is_none(), "{}", name);
+        }
+        let properties = user_session();
+        properties.insert("State", "online");
+        assert!(session_record(&properties).unwrap().is_some());
+    }
+
+    #[test]
+    fn line_prefers_display_then_tty_then_seat() {
+        let properties = user_session();
+        properties.insert("Display", ":0");
+        properties.insert("Seat", seat("seat0"));
+        assert_eq!(session_record(&properties).unwrap().unwrap().line, ":0");
+        properties.insert("Display", "");
+        assert_eq!(session_record(&properties).unwrap().unwrap().line, "pts/0");
+        properties.insert("TTY", "");
+        assert_eq!(session_record(&properties).unwrap().unwrap().line, "seat0");
+        properties.insert("Seat", seat(""));
+        assert_eq!(session_record(&properties).unwrap().unwrap().line, "");
+    }
+
+    #[test]
+    fn invalid_sessions_are_errors() {
+        let properties = user_session();
+        properties.remove("Name");
+        assert!(session_record(&properties).is_err());
+
+        let properties = user_session();
+        properties.insert("Leader", "1234");
+        assert!(session_record(&properties).is_err());
+
+        let properties = user_session();
+        properties.insert("Timestamp", u64::MAX);
+        assert!(session_record(&properties).is_err());
+    }
+}
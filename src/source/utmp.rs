@@ -0,0 +1,352 @@
+use super::{audit_session_id, Record, Records, SessionSource};
+use anyhow::{bail, Context, Error, Result};
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use mio::unix::SourceFd;
+use mio::{Interest, Registry, Token};
+use nix::unistd::Pid;
+use std::borrow::Cow;
+use std::fs;
+use std::io::ErrorKind;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use utmp_raw::{utmp, ACCOUNTING, EMPTY, USER_PROCESS};
+use utmp_rs::{ParseError, UtmpEntry, UtmpError, UtmpParser};
+
+const UTMP_PATH: &str = "/var/run/utmp";
+
+/// Size of a record in the native utmp format.
+const RECORD_SIZE: usize = mem::size_of::<utmp>();
+
+pub struct UtmpSource {
+    inotify: Inotify,
+    /// Watch on utmp file, or `None` if it hasn't been set up since the file
+    /// is missing or has been removed.
+    watch: Option<WatchDescriptor>,
+    inotify_buffer: [u8; 4096],
+}
+
+impl UtmpSource {
+    pub fn new() -> Result<Self> {
+        let inotify = Inotify::init().context("failed to init inotify")?;
+        Ok(UtmpSource {
+            inotify,
+            watch: None,
+            inotify_buffer: [0; 4096],
+        })
+    }
+}
+
+impl SessionSource for UtmpSource {
+    fn register(&mut self, registry: &Registry, token: Token) -> Result<()> {
+        registry
+            .register(
+                &mut SourceFd(&self.inotify.as_raw_fd()),
+                token,
+                Interest::READABLE,
+            )
+            .context("failed to register inotify")
+    }
+
+    fn handle_event(&mut self) -> Result<()> {
+        // Drain the inotify events if it's pending.
+        loop {
+            let events = match self.inotify.read_events(&mut self.inotify_buffer) {
+                Ok(events) => events,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(Error::new(e).context("failed to read inotify events")),
+            };
+            let mut count = 0;
+            for event in events {
+                // The watch is gone along with the file, so it needs to be
+                // set up again when the file comes back.
+                if event.mask.contains(EventMask::IGNORED) {
+                    self.watch = None;
+                }
+                count += 1;
+            }
+            if count == 0 {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&mut self) -> Result<Option<Records>> {
+        // Some systems don't have utmp at all, so rather than failing, report
+        // that no data is available until the file appears.
+        if self.watch.is_none() {
+            match self
+                .inotify
+                .watches()
+                .add(UTMP_PATH, WatchMask::CLOSE_WRITE)
+            {
+                Ok(watch) => self.watch = Some(watch),
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(Error::new(e).context("failed to watch utmp file")),
+            }
+        }
+
+        // Read the whole file at once, so that records can be classified
+        // before anything is decoded from them.
+        let data = match fs::read(UTMP_PATH) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                self.watch = None;
+                return Ok(None);
+            }
+            Err(e) => return Err(Error::new(e).context("failed to read utmp")),
+        };
+        let mut records = parse_records(&data)?;
+        // This reads from /proc for each record, so keep it out of the scan
+        // itself, which only has to look at the data.
+        for record in records.records.iter_mut() {
+            record.session_id = audit_session_id(Some(record.pid));
+        }
+        Ok(Some(records))
+    }
+}
+
+/// Parse user process records from the content of a utmp file.
+fn parse_records(data: &[u8]) -> Result<Records> {
+    let chunks = data.chunks_exact(RECORD_SIZE);
+    if !chunks.remainder().is_empty() {
+        bail!("failed to parse utmp: size not aligned");
+    }
+    let mut records = Vec::new();
+    let mut unrecognized = Vec::new();
+    let mut unparseable = Vec::new();
+    for (index, data) in chunks.enumerate() {
+        // Long-running systems can accumulate thousands of records, most of
+        // which are dead processes, so check the type, which is the first
+        // field, to skip them without decoding any string.
+        let ty = i16::from_ne_bytes([data[0], data[1]]);
+        match ty {
+            USER_PROCESS => {}
+            EMPTY..=ACCOUNTING => continue,
+            _ => {
+                unrecognized.push((index, ty));
+                continue;
+            }
+        }
+        match parse_user_process(data) {
+            Ok(record) => records.push(record),
+            // Don't let a single broken record fail the whole scan.
+            Err(_) => unparseable.push(index),
+        }
+    }
+    Ok(Records {
+        records,
+        unrecognized,
+        unparseable,
+    })
+}
+
+/// Parse a single user process record.
+fn parse_user_process(data: &[u8]) -> Result<Record, ParseError> {
+    let mut data = Cow::Borrowed(data);
+    let mut user = None;
+    let mut line = None;
+    let mut host = None;
+    loop {
+        // Fields filling up their whole width, e.g. very long user names,
+        // have no terminating NUL, which the parser rejects. So take such
+        // fields as is, and parse the record again with them cleared.
+        let (field, offset, bytes) = match UtmpParser::from_reader(&*data).next().unwrap() {
+            Ok(UtmpEntry::UserProcess {
+                pid,
+                user: parsed_user,
+                line: parsed_line,
+                host: parsed_host,
+                time,
+                ..
+            }) => {
+                return Ok(Record {
+                    pid: Pid::from_raw(pid),
+                    user: trim_padding(user.unwrap_or(parsed_user)),
+                    line: trim_padding(line.unwrap_or(parsed_line)),
+                    host: trim_padding(host.unwrap_or(parsed_host)),
+                    time,
+                    session_id: None,
+                });
+            }
+            Ok(_) => unreachable!(),
+            Err(ParseError::Utmp(UtmpError::InvalidUser(bytes))) => {
+                (&mut user, mem::offset_of!(utmp, ut_user), bytes)
+            }
+            Err(ParseError::Utmp(UtmpError::InvalidLine(bytes))) => {
+                (&mut line, mem::offset_of!(utmp, ut_line), bytes)
+            }
+            Err(ParseError::Utmp(UtmpError::InvalidHost(bytes))) => {
+                (&mut host, mem::offset_of!(utmp, ut_host), bytes)
+            }
+            Err(e) => return Err(e),
+        };
+        // The field may also be rejected for not being valid UTF-8.
+        let value = bytes.split(|b| *b == 0).next().unwrap();
+        *field = Some(String::from_utf8_lossy(value).into_owned());
+        data.to_mut()[offset..offset + bytes.len()].fill(0);
+    }
+}
+
+/// Remove trailing padding which some tools leave in the fixed-width fields,
+/// so that matching works on the clean strings.
+fn trim_padding(mut field: String) -> String {
+    let len = field
+        .trim_end_matches(|c: char| c == '\0' || c.is_whitespace())
+        .len();
+    field.truncate(len);
+    field
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FilterConfig;
+    use std::time::{Duration, Instant};
+    use utmp_raw::{BOOT_TIME, DEAD_PROCESS, UT_HOSTSIZE, UT_LINESIZE, UT_NAMESIZE};
+
+    fn record(ty: i16, pid: i32, user: &[u8], line: &[u8], host: &[u8]) -> Vec<u8> {
+        let mut record = vec![0; RECORD_SIZE];
+        let mut put = |offset: usize, bytes: &[u8]| {
+            record[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+        put(mem::offset_of!(utmp, ut_type), &ty.to_ne_bytes());
+        put(mem::offset_of!(utmp, ut_pid), &pid.to_ne_bytes());
+        put(mem::offset_of!(utmp, ut_user), user);
+        put(mem::offset_of!(utmp, ut_line), line);
+        put(mem::offset_of!(utmp, ut_host), host);
+        record
+    }
+
+    #[test]
+    fn unknown_record_types_are_reported() {
+        let data = [
+            record(EMPTY, 0, b"", b"", b""),
+            record(BOOT_TIME, 0, b"", b"~", b""),
+            record(USER_PROCESS, 100, b"alice", b"pts/0", b""),
+            record(42, 0, b"", b"", b""),
+            record(DEAD_PROCESS, 101, b"", b"pts/1", b""),
+            record(USER_PROCESS, 102, b"bob", b"pts/2", b"example.com"),
+            record(ACCOUNTING, 0, b"", b"", b""),
+            record(-1, 0, b"", b"", b""),
+        ]
+        .concat();
+        let records = parse_records(&data).unwrap();
+        let users = records
+            .records
+            .iter()
+            .map(|r| (r.pid.as_raw(), r.user.as_str(), r.host.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(users, [(100, "alice", ""), (102, "bob", "example.com")]);
+        assert_eq!(records.unrecognized, [(3, 42), (7, -1)]);
+    }
+
+    #[test]
+    fn padding_is_trimmed() {
+        assert_eq!(trim_padding("bob\0\0\0".to_string()), "bob");
+        assert_eq!(trim_padding("myhost   ".to_string()), "myhost");
+        assert_eq!(trim_padding("myhost \0 \0".to_string()), "myhost");
+        assert_eq!(trim_padding(" bob".to_string()), " bob");
+        assert_eq!(trim_padding("\0\0".to_string()), "");
+    }
+
+    #[test]
+    fn ignore_rules_match_clean_fields() {
+        let filter = FilterConfig {
+            ignored_hosts: vec!["myhost".to_string()],
+            ignored_users: vec!["monitoring-bot".to_string()],
+        };
+        let data = [
+            record(USER_PROCESS, 100, b"bob", b"pts/0", b"myhost   "),
+            record(USER_PROCESS, 101, b"monitoring-bot  ", b"pts/1", b""),
+            record(USER_PROCESS, 102, b"bob ", b"pts/2 ", b"otherhost "),
+        ]
+        .concat();
+        let records = parse_records(&data).unwrap().records;
+        let ignored = records
+            .iter()
+            .map(|r| filter.should_ignore(&r.user, &r.host))
+            .collect::<Vec<_>>();
+        assert_eq!(ignored, [true, true, false]);
+        assert_eq!(records[2].user, "bob");
+        assert_eq!(records[2].line, "pts/2");
+        assert_eq!(records[2].host, "otherhost");
+    }
+
+    #[test]
+    fn records_without_user_are_flagged() {
+        let filter = FilterConfig {
+            ignored_hosts: vec!["myhost".to_string()],
+            ignored_users: vec![String::new()],
+        };
+        let data = [
+            record(USER_PROCESS, 100, b"", b"pts/0", b"myhost"),
+            record(USER_PROCESS, 101, b"   ", b"pts/1", b"myhost"),
+        ]
+        .concat();
+        let records = parse_records(&data).unwrap().records;
+        assert_eq!(records.len(), 2);
+        for record in records.iter() {
+            assert_eq!(record.user, "");
+            let description = crate::describe(record, None, &filter);
+            assert!(description.label.contains(" - (no user) / "));
+            assert!(!description.should_ignore);
+        }
+    }
+
+    #[test]
+    fn full_width_fields_are_kept() {
+        let user = [b'u'; UT_NAMESIZE];
+        let line = [b'l'; UT_LINESIZE];
+        let host = [b'h'; UT_HOSTSIZE];
+        let data = [
+            record(USER_PROCESS, 100, &user, b"pts/0", b"example.com"),
+            record(USER_PROCESS, 101, &user, &line, &host),
+        ]
+        .concat();
+        let records = parse_records(&data).unwrap();
+        assert!(records.unrecognized.is_empty());
+        let first = &records.records[0];
+        assert_eq!(first.user.as_bytes(), user);
+        assert_eq!(first.line, "pts/0");
+        assert_eq!(first.host, "example.com");
+        let second = &records.records[1];
+        assert_eq!(second.user.as_bytes(), user);
+        assert_eq!(second.line.as_bytes(), line);
+        assert_eq!(second.host.as_bytes(), host);
+    }
+
+    #[test]
+    fn broken_records_are_reported() {
+        let mut broken = record(USER_PROCESS, 101, b"bob", b"pts/1", b"");
+        // Negative microseconds are invalid.
+        let usec = mem::offset_of!(utmp, ut_tv.tv_usec)..mem::offset_of!(utmp, ut_addr_v6);
+        broken[usec].fill(0xff);
+        let data = [record(USER_PROCESS, 100, b"alice", b"pts/0", b""), broken].concat();
+        let records = parse_records(&data).unwrap();
+        assert_eq!(records.records.len(), 1);
+        assert!(records.unrecognized.is_empty());
+        assert_eq!(records.unparseable, [1]);
+    }
+
+    #[test]
+    fn large_file_scan_stays_within_budget() {
+        // Mostly dead processes, like on a long-running server.
+        let data = (0..10_000)
+            .flat_map(|i| match i % 100 {
+                0 => record(USER_PROCESS, i, b"user", b"pts/0", b"example.com"),
+                _ => record(DEAD_PROCESS, i, b"", b"pts/0", b""),
+            })
+            .collect::<Vec<_>>();
+        let start = Instant::now();
+        let records = parse_records(&data).unwrap();
+        let elapsed = start.elapsed();
+        assert_eq!(records.records.len(), 100);
+        assert!(records.unrecognized.is_empty());
+        assert!(
+            elapsed < Duration::from_millis(10),
+            "scan took {:?}",
+            elapsed
+        );
+    }
+}